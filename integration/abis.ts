@@ -61,7 +61,7 @@ export const vaultAbi = [
     type: "function",
   },
   {
-    inputs: [],
+    inputs: [{ internalType: "uint256", name: "deposit_id", type: "uint256" }],
     name: "claimRewards",
     outputs: [],
     stateMutability: "nonpayable",
@@ -70,12 +70,12 @@ export const vaultAbi = [
   {
     inputs: [{ internalType: "uint256", name: "lock_period", type: "uint256" }],
     name: "deposit",
-    outputs: [],
+    outputs: [{ internalType: "uint256", name: "", type: "uint256" }],
     stateMutability: "payable",
     type: "function",
   },
   {
-    inputs: [],
+    inputs: [{ internalType: "uint256", name: "deposit_id", type: "uint256" }],
     name: "emergencyWithdraw",
     outputs: [],
     stateMutability: "nonpayable",
@@ -89,17 +89,28 @@ export const vaultAbi = [
     type: "function",
   },
   {
-    inputs: [{ internalType: "address", name: "user", type: "address" }],
+    inputs: [
+      { internalType: "address", name: "user", type: "address" },
+      { internalType: "uint256", name: "deposit_id", type: "uint256" },
+    ],
     name: "getDepositInfo",
     outputs: [
       { internalType: "uint256", name: "", type: "uint256" },
       { internalType: "uint256", name: "", type: "uint256" },
       { internalType: "uint256", name: "", type: "uint256" },
       { internalType: "uint256", name: "", type: "uint256" },
+      { internalType: "bytes32", name: "", type: "bytes32" },
     ],
     stateMutability: "view",
     type: "function",
   },
+  {
+    inputs: [{ internalType: "address", name: "user", type: "address" }],
+    name: "getDepositCount",
+    outputs: [{ internalType: "uint256", name: "", type: "uint256" }],
+    stateMutability: "view",
+    type: "function",
+  },
   {
     inputs: [],
     name: "getEmergencyMode",
@@ -122,6 +133,7 @@ export const vaultAbi = [
         name: "time_bonus_multiplier",
         type: "uint256",
       },
+      { internalType: "address", name: "token", type: "address" },
     ],
     name: "initialize",
     outputs: [],
@@ -136,7 +148,7 @@ export const vaultAbi = [
     type: "function",
   },
   {
-    inputs: [],
+    inputs: [{ internalType: "uint256", name: "deposit_id", type: "uint256" }],
     name: "withdraw",
     outputs: [],
     stateMutability: "nonpayable",
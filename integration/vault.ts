@@ -9,12 +9,16 @@ export class TimeLockedVault {
     this.contractAddress = contractAddress;
   }
 
-  async initialize(baseRewardRate: bigint, timeBonusMultiplier: bigint) {
+  async initialize(
+    baseRewardRate: bigint,
+    timeBonusMultiplier: bigint,
+    token: `0x${string}` = "0x0000000000000000000000000000000000000000"
+  ) {
     const txHash = await walletClient.writeContract({
       address: this.contractAddress,
       abi: vaultAbi,
       functionName: "initialize",
-      args: [baseRewardRate, timeBonusMultiplier],
+      args: [baseRewardRate, timeBonusMultiplier, token],
     });
     console.log(`Initialize tx: ${txHash}`);
     return txHash;
@@ -33,34 +37,34 @@ export class TimeLockedVault {
     return txHash;
   }
 
-  async withdraw() {
+  async withdraw(depositId: bigint = 0n) {
     const txHash = await walletClient.writeContract({
       address: this.contractAddress,
       abi: vaultAbi,
       functionName: "withdraw",
-      args: [],
+      args: [depositId],
     });
     console.log(`Withdraw tx: ${txHash}`);
     return txHash;
   }
 
-  async emergencyWithdraw() {
+  async emergencyWithdraw(depositId: bigint = 0n) {
     const txHash = await walletClient.writeContract({
       address: this.contractAddress,
       abi: vaultAbi,
       functionName: "emergencyWithdraw",
-      args: [],
+      args: [depositId],
     });
     console.log(`Emergency withdraw tx: ${txHash}`);
     return txHash;
   }
 
-  async claimRewards() {
+  async claimRewards(depositId: bigint = 0n) {
     const txHash = await walletClient.writeContract({
       address: this.contractAddress,
       abi: vaultAbi,
       functionName: "claimRewards",
-      args: [],
+      args: [depositId],
     });
     console.log(`Claim rewards tx: ${txHash}`);
     return txHash;
@@ -98,21 +102,32 @@ export class TimeLockedVault {
     return rewards;
   }
 
-  async getDepositInfo(userAddress: `0x${string}`) {
+  async getDepositInfo(userAddress: `0x${string}`, depositId: bigint = 0n) {
     const info = await walletClient.readContract({
       address: this.contractAddress,
       abi: vaultAbi,
       functionName: "getDepositInfo",
-      args: [userAddress],
+      args: [userAddress, depositId],
     });
     return {
       amount: info[0],
       depositTime: info[1],
       lockPeriod: info[2],
       lastClaimTime: info[3],
+      label: info[4],
     };
   }
 
+  async getDepositCount(userAddress: `0x${string}`) {
+    const count = await walletClient.readContract({
+      address: this.contractAddress,
+      abi: vaultAbi,
+      functionName: "getDepositCount",
+      args: [userAddress],
+    });
+    return count;
+  }
+
   async getTotalLocked() {
     const total = await walletClient.readContract({
       address: this.contractAddress,